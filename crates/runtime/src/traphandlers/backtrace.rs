@@ -19,11 +19,28 @@
 //! true` setting. Then we can do simple frame pointer traversal starting at the
 //! exit FP and stopping once we reach the entry SP (meaning that the next older
 //! frame is a host frame).
+//!
+//! Frame-pointer chaining isn't always available, though: an embedder may
+//! disable `preserve_frame_pointers` for code-size or performance reasons,
+//! or the target architecture may not have an `arch` module below at all.
+//! When the `unwind-fallback` feature is enabled, [`cfi::fallback_enabled`]
+//! reports whether we should fall back to walking the Wasm region's system
+//! unwind tables (`.eh_frame`-style DWARF CFI) instead; either way
+//! `trace_through_wasm`'s contract -- one `Frame` per physical frame,
+//! stopping at `trampoline_sp` -- is identical.
 
 use crate::traphandlers::{tls, CallThreadState};
 use cfg_if::cfg_if;
 use std::ops::ControlFlow;
 
+mod symbolicate;
+pub use symbolicate::{FrameSymbol, ModuleInfo, ModuleLookup};
+
+#[cfg(feature = "unwind-fallback")]
+mod cfi;
+#[cfg(feature = "unwind-fallback")]
+pub use cfi::{set_unwind_fallback_enabled, set_unwind_info_lookup, UnwindInfoLookup, UnwindModuleInfo};
+
 // Architecture-specific bits for stack walking. Each of these modules should
 // define and export the following functions:
 //
@@ -32,6 +49,12 @@ use std::ops::ControlFlow;
 // * `fn reached_entry_sp(fp: usize, first_wasm_sp: usize) -> bool`
 // * `fn assert_entry_sp_is_aligned(sp: usize)`
 // * `fn assert_fp_is_aligned(fp: usize)`
+//
+// On architectures with none of these, the `unwind-fallback` feature must be
+// enabled so that `cfi::trace_through_wasm` can be used in place of the
+// frame-pointer walk below. `cfi` only has CFI register conventions for
+// x86_64 and aarch64, though, so on any other architecture enabling the
+// feature still fails the build, in `cfi`.
 cfg_if! {
     if #[cfg(target_arch = "x86_64")] {
         mod x86_64;
@@ -45,14 +68,24 @@ cfg_if! {
     } else if #[cfg(target_arch = "riscv64")] {
         mod riscv64;
         use riscv64 as arch;
+    } else if #[cfg(feature = "unwind-fallback")] {
+        // No frame-pointer-based `arch` module for this target; the CFI
+        // fallback in `trace_through_wasm` below is the only option, and
+        // `cfi::fallback_enabled` unconditionally reports `true` for it.
     } else {
-        compile_error!("unsupported architecture");
+        compile_error!(
+            "unsupported architecture: enable the `unwind-fallback` feature \
+             to walk the stack via the system unwinder instead of frame pointers"
+        );
     }
 }
 
 /// A WebAssembly stack trace.
 #[derive(Debug)]
-pub struct Backtrace(Vec<Frame>);
+pub struct Backtrace {
+    frames: Vec<Frame>,
+    trap_info: Option<TrapInfo>,
+}
 
 /// A stack frame within a Wasm stack trace.
 #[derive(Debug)]
@@ -61,6 +94,65 @@ pub struct Frame {
     fp: usize,
 }
 
+/// Metadata about the trap that caused a [`Backtrace`] to be captured, if
+/// it was captured because of a trap at all (see
+/// [`Backtrace::trap_info`]).
+#[derive(Debug, Clone, Copy)]
+pub struct TrapInfo {
+    code: wasmtime_environ::Trap,
+    trapping_frame: usize,
+    faulting_addr: Option<u64>,
+}
+
+impl TrapInfo {
+    /// Which kind of trap occurred (e.g. memory out-of-bounds, integer
+    /// div-by-zero, unreachable, stack overflow, indirect-call type
+    /// mismatch).
+    pub fn code(&self) -> wasmtime_environ::Trap {
+        self.code
+    }
+
+    /// The index, within [`Backtrace::frames`], of the frame in which the
+    /// trap actually occurred, as opposed to the frames merely on the stack
+    /// above it.
+    pub fn trapping_frame(&self) -> usize {
+        self.trapping_frame
+    }
+
+    /// For memory-access traps, the faulting address's offset from the
+    /// start of the linear memory that was being accessed.
+    pub fn faulting_addr(&self) -> Option<u64> {
+        self.faulting_addr
+    }
+}
+
+/// The result of [`Backtrace::raise_early_exit`]: a host-supplied
+/// `payload` paired with the Wasm frames that were live when the early exit
+/// was requested.
+#[derive(Debug)]
+pub struct EarlyExit<T> {
+    payload: T,
+    backtrace: Backtrace,
+}
+
+impl<T> EarlyExit<T> {
+    /// The host-supplied payload that triggered this early exit.
+    pub fn payload(&self) -> &T {
+        &self.payload
+    }
+
+    /// The Wasm frames that were live when the early exit was requested.
+    pub fn backtrace(&self) -> &Backtrace {
+        &self.backtrace
+    }
+
+    /// Consume this `EarlyExit`, returning its payload and backtrace
+    /// separately.
+    pub fn into_parts(self) -> (T, Backtrace) {
+        (self.payload, self.backtrace)
+    }
+}
+
 impl Frame {
     /// Get this frame's program counter.
     pub fn pc(&self) -> usize {
@@ -76,14 +168,17 @@ impl Frame {
 impl Backtrace {
     /// Returns an empty backtrace
     pub fn empty() -> Backtrace {
-        Backtrace(Vec::new())
+        Backtrace {
+            frames: Vec::new(),
+            trap_info: None,
+        }
     }
 
     /// Capture the current Wasm stack in a backtrace.
     pub fn new() -> Backtrace {
         tls::with(|state| match state {
             Some(state) => unsafe { Self::new_with_trap_state(state, None) },
-            None => Backtrace(vec![]),
+            None => Backtrace::empty(),
         })
     }
 
@@ -101,7 +196,34 @@ impl Backtrace {
             frames.push(frame);
             ControlFlow::Continue(())
         });
-        Backtrace(frames)
+        Backtrace {
+            frames,
+            trap_info: None,
+        }
+    }
+
+    /// Attach trap classification metadata to this backtrace, identifying
+    /// the trap's kind and (for memory faults) the faulting address.
+    ///
+    /// This is meant to be chained directly onto the trap handler's
+    /// existing `new_with_trap_state(state, Some((pc, fp)))` call --
+    /// `state` and `pc_and_fp` are exactly the arguments already passed to
+    /// that call -- so that classifying a trap doesn't require threading
+    /// anything new through `trace_with_trap_state`'s frame walk. The trap
+    /// is always attributed to the innermost frame of the resulting
+    /// backtrace, since that is the frame whose PC the trap handler
+    /// observed when it recorded `pc_and_fp`.
+    pub(crate) fn with_trap_info(
+        mut self,
+        code: wasmtime_environ::Trap,
+        faulting_addr: Option<u64>,
+    ) -> Backtrace {
+        self.trap_info = Some(TrapInfo {
+            code,
+            trapping_frame: 0,
+            faulting_addr,
+        });
+        self
     }
 
     /// Walk the current Wasm stack, calling `f` for each frame we walk.
@@ -221,90 +343,248 @@ impl Backtrace {
         assert_ne!(fp, 0);
         assert_ne!(trampoline_sp, 0);
 
-        arch::assert_entry_sp_is_aligned(trampoline_sp);
-
-        loop {
-            // At the start of each iteration of the loop, we know that `fp` is
-            // a frame pointer from Wasm code. Therefore, we know it is not
-            // being used as an extra general-purpose register, and it is safe
-            // dereference to get the PC and the next older frame pointer.
-
-            // The stack grows down, and therefore any frame pointer we are
-            // dealing with should be less than the stack pointer on entry
-            // to Wasm.
-            assert!(trampoline_sp >= fp, "{trampoline_sp:#x} >= {fp:#x}");
-
-            arch::assert_fp_is_aligned(fp);
-
-            log::trace!("--- Tracing through one Wasm frame ---");
-            log::trace!("pc = {:p}", pc as *const ());
-            log::trace!("fp = {:p}", fp as *const ());
-
-            f(Frame { pc, fp })?;
-
-            pc = arch::get_next_older_pc_from_fp(fp);
-
-            // We rely on this offset being zero for all supported architectures
-            // in `crates/cranelift/src/component/compiler.rs` when we set the
-            // Wasm exit FP. If this ever changes, we will need to update that
-            // code as well!
-            assert_eq!(arch::NEXT_OLDER_FP_FROM_FP_OFFSET, 0);
-
-            // Get the next older frame pointer from the current Wasm frame
-            // pointer.
-            //
-            // The next older frame pointer may or may not be a Wasm frame's
-            // frame pointer, but it is trusted either way (i.e. is actually a
-            // frame pointer and not being used as a general-purpose register)
-            // because we always enter Wasm from the host via a trampoline, and
-            // this trampoline maintains a proper frame pointer.
-            //
-            // We want to detect when we've reached the trampoline, and break
-            // out of this stack-walking loop. All of our architectures' stacks
-            // grow down and look something vaguely like this:
-            //
-            //     | ...               |
-            //     | Native Frames     |
-            //     | ...               |
-            //     |-------------------|
-            //     | ...               | <-- Trampoline FP            |
-            //     | Trampoline Frame  |                              |
-            //     | ...               | <-- Trampoline SP            |
-            //     |-------------------|                            Stack
-            //     | Return Address    |                            Grows
-            //     | Previous FP       | <-- Wasm FP                Down
-            //     | ...               |                              |
-            //     | Wasm Frames       |                              |
-            //     | ...               |                              V
-            //
-            // The trampoline records its own stack pointer (`trampoline_sp`),
-            // which is guaranteed to be above all Wasm frame pointers but at or
-            // below its own frame pointer. It is usually two words above the
-            // Wasm frame pointer (at least on x86-64, exact details vary across
-            // architectures) but not always: if the first Wasm function called
-            // by the host has many arguments, some of them could be passed on
-            // the stack in between the return address and the trampoline's
-            // frame.
-            //
-            // To check when we've reached the trampoline frame, it is therefore
-            // sufficient to check when the next frame pointer is greater than
-            // or equal to `trampoline_sp` (except s390x, where it needs to be
-            // strictly greater than).
-            let next_older_fp = *(fp as *mut usize).add(arch::NEXT_OLDER_FP_FROM_FP_OFFSET);
-            if arch::reached_entry_sp(next_older_fp, trampoline_sp) {
-                log::trace!("=== Done tracing contiguous sequence of Wasm frames ===");
-                return ControlFlow::Continue(());
-            }
+        #[cfg(feature = "unwind-fallback")]
+        if cfi::fallback_enabled() {
+            return cfi::trace_through_wasm(pc, fp, trampoline_sp, f);
+        }
+
+        #[cfg(not(any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "s390x",
+            target_arch = "riscv64",
+        )))]
+        unreachable!(
+            "cfi::fallback_enabled() is unconditionally true without a \
+             frame-pointer-based `arch` module"
+        );
+
+        #[cfg(any(
+            target_arch = "x86_64",
+            target_arch = "aarch64",
+            target_arch = "s390x",
+            target_arch = "riscv64",
+        ))]
+        {
+            arch::assert_entry_sp_is_aligned(trampoline_sp);
+
+            loop {
+                // At the start of each iteration of the loop, we know that `fp` is
+                // a frame pointer from Wasm code. Therefore, we know it is not
+                // being used as an extra general-purpose register, and it is safe
+                // dereference to get the PC and the next older frame pointer.
+
+                // The stack grows down, and therefore any frame pointer we are
+                // dealing with should be less than the stack pointer on entry
+                // to Wasm.
+                assert!(trampoline_sp >= fp, "{trampoline_sp:#x} >= {fp:#x}");
 
-            // Because the stack always grows down, the older FP must be greater
-            // than the current FP.
-            assert!(next_older_fp > fp, "{next_older_fp:#x} > {fp:#x}");
-            fp = next_older_fp;
+                arch::assert_fp_is_aligned(fp);
+
+                log::trace!("--- Tracing through one Wasm frame ---");
+                log::trace!("pc = {:p}", pc as *const ());
+                log::trace!("fp = {:p}", fp as *const ());
+
+                f(Frame { pc, fp })?;
+
+                pc = arch::get_next_older_pc_from_fp(fp);
+
+                // We rely on this offset being zero for all supported architectures
+                // in `crates/cranelift/src/component/compiler.rs` when we set the
+                // Wasm exit FP. If this ever changes, we will need to update that
+                // code as well!
+                assert_eq!(arch::NEXT_OLDER_FP_FROM_FP_OFFSET, 0);
+
+                // Get the next older frame pointer from the current Wasm frame
+                // pointer.
+                //
+                // The next older frame pointer may or may not be a Wasm frame's
+                // frame pointer, but it is trusted either way (i.e. is actually a
+                // frame pointer and not being used as a general-purpose register)
+                // because we always enter Wasm from the host via a trampoline, and
+                // this trampoline maintains a proper frame pointer.
+                //
+                // We want to detect when we've reached the trampoline, and break
+                // out of this stack-walking loop. All of our architectures' stacks
+                // grow down and look something vaguely like this:
+                //
+                //     | ...               |
+                //     | Native Frames     |
+                //     | ...               |
+                //     |-------------------|
+                //     | ...               | <-- Trampoline FP            |
+                //     | Trampoline Frame  |                              |
+                //     | ...               | <-- Trampoline SP            |
+                //     |-------------------|                            Stack
+                //     | Return Address    |                            Grows
+                //     | Previous FP       | <-- Wasm FP                Down
+                //     | ...               |                              |
+                //     | Wasm Frames       |                              |
+                //     | ...               |                              V
+                //
+                // The trampoline records its own stack pointer (`trampoline_sp`),
+                // which is guaranteed to be above all Wasm frame pointers but at or
+                // below its own frame pointer. It is usually two words above the
+                // Wasm frame pointer (at least on x86-64, exact details vary across
+                // architectures) but not always: if the first Wasm function called
+                // by the host has many arguments, some of them could be passed on
+                // the stack in between the return address and the trampoline's
+                // frame.
+                //
+                // To check when we've reached the trampoline frame, it is therefore
+                // sufficient to check when the next frame pointer is greater than
+                // or equal to `trampoline_sp` (except s390x, where it needs to be
+                // strictly greater than).
+                let next_older_fp = *(fp as *mut usize).add(arch::NEXT_OLDER_FP_FROM_FP_OFFSET);
+                if arch::reached_entry_sp(next_older_fp, trampoline_sp) {
+                    log::trace!("=== Done tracing contiguous sequence of Wasm frames ===");
+                    return ControlFlow::Continue(());
+                }
+
+                // Because the stack always grows down, the older FP must be greater
+                // than the current FP.
+                assert!(next_older_fp > fp, "{next_older_fp:#x} > {fp:#x}");
+                fp = next_older_fp;
+            }
         }
     }
 
     /// Iterate over the frames inside this backtrace.
     pub fn frames<'a>(&'a self) -> impl ExactSizeIterator<Item = &'a Frame> + 'a {
-        self.0.iter()
+        self.frames.iter()
+    }
+
+    /// If this backtrace was captured because of a trap (as opposed to
+    /// e.g. [`Backtrace::new`] or a host-initiated early exit), metadata
+    /// about that trap: its kind, which frame it occurred in, and (for
+    /// memory faults) the faulting address.
+    pub fn trap_info(&self) -> Option<&TrapInfo> {
+        self.trap_info.as_ref()
+    }
+
+    /// The frame in which the trap that produced this backtrace actually
+    /// occurred, as opposed to the frames merely on the stack above it.
+    ///
+    /// Returns `None` if this backtrace wasn't captured because of a trap.
+    pub fn trapping_frame(&self) -> Option<&Frame> {
+        let trap_info = self.trap_info.as_ref()?;
+        Some(&self.frames[trap_info.trapping_frame])
+    }
+
+    /// Capture a backtrace for a host-initiated early exit.
+    ///
+    /// Unlike a trap, an early exit is raised from a host function, so the
+    /// Wasm-to-host exit trampoline has already run and recorded the last
+    /// Wasm PC/FP in `VMRuntimeLimits`; there's nothing to plumb through
+    /// explicitly.
+    unsafe fn new_with_early_exit<T>(state: &CallThreadState, payload: T) -> EarlyExit<T> {
+        EarlyExit {
+            payload,
+            backtrace: Self::new_with_trap_state(state, None),
+        }
+    }
+
+    /// Abandon the current Wasm invocation, unwinding back to the nearest
+    /// `catch_traps` boundary with a captured backtrace of the Wasm frames
+    /// that were live at this point.
+    ///
+    /// Call this from a host function to implement things like cooperative
+    /// cancellation or `exit(n)`-style control flow without fabricating a
+    /// fake trap. `payload` is an arbitrary host-supplied value (e.g. a
+    /// boxed error or an exit code) that is surfaced, paired with the
+    /// backtrace, to whatever [`Backtrace::catch_early_exit`] (or
+    /// `catch_traps`) is waiting at the boundary.
+    ///
+    /// This unwinds by panicking with the captured [`EarlyExit<T>`] as the
+    /// panic payload -- the same mechanism already used to propagate a host
+    /// function's own panics back across the Wasm-to-host boundary (see
+    /// `CallThreadState::unwind_with(UnwindReason::Panic(..))`), so
+    /// `catch_traps` needs no changes to let an early exit unwind through
+    /// it; it only needs to downcast the panic payload to `EarlyExit<T>`
+    /// before assuming it's an ordinary panic.
+    pub fn raise_early_exit<T: Send + 'static>(payload: T) -> ! {
+        let early_exit = tls::with(|state| match state {
+            Some(state) => unsafe { Self::new_with_early_exit(state, payload) },
+            None => EarlyExit {
+                payload,
+                backtrace: Backtrace::empty(),
+            },
+        });
+        std::panic::resume_unwind(Box::new(early_exit))
+    }
+
+    /// Run `f`, catching a [`Backtrace::raise_early_exit`] request carrying
+    /// a `T` payload and returning its [`EarlyExit<T>`] instead of letting
+    /// it unwind further.
+    ///
+    /// Any other panic -- including an early exit carrying a different
+    /// payload type -- is re-thrown unchanged. This is the embedder-facing
+    /// counterpart to `raise_early_exit`, suitable for catching an early
+    /// exit at a call boundary that isn't `catch_traps` itself (e.g. in
+    /// tests, or in an embedding that invokes Wasm without going through
+    /// the JIT call path at all).
+    pub fn catch_early_exit<T: 'static, R>(
+        f: impl FnOnce() -> R + std::panic::UnwindSafe,
+    ) -> Result<R, EarlyExit<T>> {
+        match std::panic::catch_unwind(f) {
+            Ok(value) => Ok(value),
+            Err(panic_payload) => match panic_payload.downcast::<EarlyExit<T>>() {
+                Ok(early_exit) => Err(*early_exit),
+                Err(panic_payload) => std::panic::resume_unwind(panic_payload),
+            },
+        }
+    }
+
+    /// Resolve each frame in this backtrace to its owning module and, when
+    /// debug info is present, a human-readable function name, file, and
+    /// line.
+    ///
+    /// `lookup` is consulted for each frame's `pc` to find the compiled
+    /// module that contains it; frames whose `pc` can't be attributed to any
+    /// currently-registered module get an empty inner `Vec`. The returned outer `Vec` has
+    /// one entry per physical `Frame` in this backtrace, in the same order
+    /// as [`Backtrace::frames`]; the inner `Vec` holds a single
+    /// [`FrameSymbol`] unless the frame was produced by inlining, in which
+    /// case it holds one symbol per inlined logical frame.
+    pub fn symbolize(&self, lookup: &dyn ModuleLookup) -> Vec<Vec<FrameSymbol>> {
+        self.frames
+            .iter()
+            .map(|frame| symbolicate::symbolize_frame(lookup, frame))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn early_exit_round_trips_payload_with_no_vm_state() {
+        // Outside of any Wasm invocation, `tls::with` sees no
+        // `CallThreadState`, so this exercises the `None` branch of
+        // `raise_early_exit` and should come back with an empty backtrace.
+        let result: Result<(), EarlyExit<&str>> =
+            Backtrace::catch_early_exit(|| Backtrace::raise_early_exit("cancelled"));
+        let early_exit = result.unwrap_err();
+        assert_eq!(*early_exit.payload(), "cancelled");
+        assert_eq!(early_exit.backtrace().frames().len(), 0);
+    }
+
+    #[test]
+    fn catch_early_exit_reraises_ordinary_panics() {
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _: Result<(), EarlyExit<&str>> =
+                Backtrace::catch_early_exit(|| panic!("not an early exit"));
+        }));
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn catch_early_exit_reraises_early_exit_of_a_different_payload_type() {
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _: Result<(), EarlyExit<u32>> =
+                Backtrace::catch_early_exit(|| Backtrace::raise_early_exit("wrong payload type"));
+        }));
+        assert!(result.is_err());
     }
 }
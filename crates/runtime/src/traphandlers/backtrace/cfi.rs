@@ -0,0 +1,224 @@
+//! Fallback stack walking that does not depend on frame-pointer chaining.
+//!
+//! The rest of this module's siblings (`x86_64`, `aarch64`, `s390x`,
+//! `riscv64`) walk the stack by following the chain of saved frame
+//! pointers, which requires Cranelift's `preserve_frame_pointers = true`
+//! setting. Some embedders disable that setting for code-size or
+//! performance reasons, and some architectures we'd like to support don't
+//! have an `arch` module here at all. For both cases we fall back to the
+//! host platform's unwind tables (`.eh_frame`-style DWARF CFI) to recover
+//! the next older PC and CFA, bounded by the same entry-SP/exit-FP ranges
+//! the frame-pointer walker uses.
+//!
+//! This path is slower than frame-pointer chaining -- it has to parse and
+//! evaluate CFI programs instead of dereferencing a couple of words -- so
+//! it is opt-in via the `unwind-fallback` feature and only actually used
+//! when `fallback_enabled` reports that frame pointers aren't available.
+
+use std::ops::ControlFlow;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::OnceLock;
+
+use gimli::{BaseAddresses, CfaRule, EhFrame, NativeEndian, Register, RegisterRule, UnwindContext};
+
+use super::Frame;
+
+/// Whether the CFI-based fallback walker should be used in place of the
+/// frame-pointer walker.
+///
+/// This defaults to `false` on architectures with a frame-pointer-based
+/// `arch` module (frame-pointer chaining stays the fast default path) and
+/// is flipped by the embedder, through `wasmtime::Config`, when it
+/// disables `preserve_frame_pointers`. On architectures with no `arch`
+/// module in this crate it defaults to `true` and stays `true`.
+///
+/// Defaulting this on for an architecture whose register conventions
+/// `arch_fp_register`/`arch_return_address_register` below don't actually
+/// implement would build cleanly and then panic the first time a backtrace
+/// is captured, so any architecture in this list (i.e. every architecture
+/// without a frame-pointer-based `arch` module) must have a real branch in
+/// the `cfg_if!` below -- anything else is a compile error there instead.
+static FALLBACK_ENABLED: AtomicBool = AtomicBool::new(cfg!(not(any(
+    target_arch = "x86_64",
+    target_arch = "aarch64",
+    target_arch = "s390x",
+    target_arch = "riscv64",
+))));
+
+pub(super) fn fallback_enabled() -> bool {
+    FALLBACK_ENABLED.load(Ordering::Relaxed)
+}
+
+/// Crosses the `wasmtime-runtime` / `wasmtime` crate boundary: called by
+/// `wasmtime::Config` (which cannot reach a `pub(crate)` item of this
+/// crate) to force the fallback walker on even for architectures that do
+/// have a frame-pointer-based `arch` module, e.g. because
+/// `preserve_frame_pointers` was disabled. On architectures without such a
+/// module the fallback walker stays enabled whatever `enabled` is.
+pub fn set_unwind_fallback_enabled(enabled: bool) {
+    let required = cfg!(not(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "s390x",
+        target_arch = "riscv64",
+    )));
+    FALLBACK_ENABLED.store(enabled || required, Ordering::Relaxed);
+}
+
+/// A source of `.eh_frame`-style CFI data for whatever compiled module
+/// contains a given `pc`, supplied by the embedder.
+///
+/// This crosses the same `wasmtime-runtime` / `wasmtime` boundary as
+/// [`set_unwind_fallback_enabled`]: `wasmtime::Module`'s code-memory
+/// registry is the only thing that knows where a module's CFI section
+/// lives, so `wasmtime::Config` registers an implementation of this trait
+/// (via [`set_unwind_info_lookup`]) alongside enabling the fallback.
+pub trait UnwindInfoLookup: Send + Sync {
+    /// Find the module containing `pc`, returning its CFI section bytes
+    /// and the address they were loaded at (the base that the section's
+    /// own internal offsets are relative to).
+    fn unwind_info_for_pc(&self, pc: usize) -> Option<UnwindModuleInfo<'_>>;
+}
+
+/// The CFI data needed to unwind through one compiled module, as returned
+/// by [`UnwindInfoLookup::unwind_info_for_pc`].
+pub struct UnwindModuleInfo<'a> {
+    /// The module's `.eh_frame` (or `.debug_frame`) section contents.
+    pub eh_frame: &'a [u8],
+    /// The address this module's code (and hence its CFI section's
+    /// `.eh_frame`-relative offsets) was loaded at.
+    pub load_address: usize,
+}
+
+static UNWIND_INFO_LOOKUP: OnceLock<Box<dyn UnwindInfoLookup>> = OnceLock::new();
+
+/// Crosses the `wasmtime-runtime` / `wasmtime` crate boundary: called once,
+/// by `wasmtime::Config`, to register the embedder's source of CFI data
+/// before the `unwind-fallback` feature's walker can be used.
+///
+/// Panics if called more than once.
+pub fn set_unwind_info_lookup(lookup: Box<dyn UnwindInfoLookup>) {
+    UNWIND_INFO_LOOKUP
+        .set(lookup)
+        .unwrap_or_else(|_| panic!("`set_unwind_info_lookup` must only be called once"));
+}
+
+/// Walk through a contiguous sequence of Wasm frames starting at `pc`/`fp`
+/// and ending at `trampoline_sp`, using the system unwinder's CFI tables
+/// instead of frame-pointer chaining.
+///
+/// This keeps the exact same contract as the frame-pointer walker: it
+/// stops once the current frame's CFA reaches `trampoline_sp`, and it
+/// calls `f` with exactly one `Frame` per physical frame, so downstream
+/// symbolication (`Backtrace::symbolize`) is unaffected by which walker
+/// produced the trace.
+pub(super) unsafe fn trace_through_wasm(
+    mut pc: usize,
+    mut fp: usize,
+    trampoline_sp: usize,
+    mut f: impl FnMut(Frame) -> ControlFlow<()>,
+) -> ControlFlow<()> {
+    log::trace!("=== Tracing through contiguous sequence of Wasm frames (CFI fallback) ===");
+
+    loop {
+        f(Frame { pc, fp })?;
+
+        let (next_pc, cfa, next_fp) = unwind_step(pc, fp);
+
+        if cfa >= trampoline_sp {
+            log::trace!("=== Done tracing contiguous sequence of Wasm frames (CFI fallback) ===");
+            return ControlFlow::Continue(());
+        }
+
+        assert!(next_fp > fp, "{next_fp:#x} > {fp:#x}");
+        pc = next_pc;
+        fp = next_fp;
+    }
+}
+
+/// Use the host platform's unwind tables to step from the frame at `pc`
+/// (whose frame-pointer-register value is `fp`) to the next older one,
+/// returning its return address, this frame's CFA (used only to bound the
+/// walk against `trampoline_sp`), and the next frame's live
+/// frame-pointer-register value.
+///
+/// Unlike the frame-pointer walker, there's no well-defined "ran off the
+/// end of Wasm, must be a bug" condition to recover from here short of a
+/// malformed or missing CFI program, which would mean we've already
+/// produced a backtrace we can't trust -- so this reports those failures
+/// by panicking rather than by silently truncating the trace.
+fn unwind_step(pc: usize, fp: usize) -> (usize, usize, usize) {
+    let lookup = UNWIND_INFO_LOOKUP.get().unwrap_or_else(|| {
+        panic!(
+            "the `unwind-fallback` stack walker is enabled but no `UnwindInfoLookup` was \
+             registered via `cfi::set_unwind_info_lookup`; an embedder must register one \
+             (typically from `wasmtime::Config`) before capturing any backtrace"
+        )
+    });
+    let module = lookup
+        .unwind_info_for_pc(pc)
+        .unwrap_or_else(|| panic!("no compiled module registered for pc = {pc:#x}"));
+
+    let eh_frame = EhFrame::new(module.eh_frame, NativeEndian);
+    let bases = BaseAddresses::default().set_eh_frame(module.load_address as u64);
+    let mut ctx = UnwindContext::new();
+    let row = eh_frame
+        .unwind_info_for_address(&bases, &mut ctx, pc as u64, EhFrame::cie_from_offset)
+        .unwrap_or_else(|e| panic!("no CFI unwind info for pc = {pc:#x}: {e}"));
+
+    // This frame's CFA, computed from the *live* frame-pointer-register
+    // value we were handed -- not the previous frame's CFA, which is a
+    // different quantity and would desync the chain after the first hop.
+    let cfa = match row.cfa() {
+        CfaRule::RegisterAndOffset { register, offset } if *register == arch_fp_register() => {
+            (fp as i64 + offset) as usize
+        }
+        rule => panic!("unsupported CFA rule at pc = {pc:#x}: {rule:?}"),
+    };
+
+    let read_saved = |rule, what| match rule {
+        RegisterRule::Offset(offset) => unsafe { *((cfa as i64 + offset) as *const usize) },
+        rule => panic!("unsupported {what} rule at pc = {pc:#x}: {rule:?}"),
+    };
+    let return_address = read_saved(row.register(arch_return_address_register()), "return-address");
+    // The next (older) frame's frame-pointer-register value, recovered from
+    // wherever this frame's CFI program says the callee-saved register was
+    // spilled relative to *this* frame's CFA -- this is what must be fed
+    // back in as `fp` on the next call, not `cfa` itself.
+    let next_fp = read_saved(row.register(arch_fp_register()), "frame-pointer");
+
+    (return_address, cfa, next_fp)
+}
+
+cfg_if::cfg_if! {
+    if #[cfg(target_arch = "x86_64")] {
+        fn arch_fp_register() -> Register {
+            gimli::X86_64::RBP
+        }
+
+        fn arch_return_address_register() -> Register {
+            gimli::X86_64::RA
+        }
+    } else if #[cfg(target_arch = "aarch64")] {
+        fn arch_fp_register() -> Register {
+            gimli::AArch64::X29
+        }
+
+        fn arch_return_address_register() -> Register {
+            gimli::AArch64::X30
+        }
+    } else {
+        // Every architecture other than x86_64 and aarch64 reaches this
+        // branch: s390x and riscv64, which also have a frame-pointer-based
+        // `arch` module, and every architecture without one, where the
+        // `unwind-fallback` walker defaults on and would be the only stack
+        // walker available (see `FALLBACK_ENABLED`'s default above). Rather
+        // than let any of them build and then panic on the first backtrace,
+        // the build fails here, since this module has no CFI register
+        // conventions for them.
+        compile_error!(
+            "the `unwind-fallback` feature does not yet implement CFI register conventions \
+             for this architecture"
+        );
+    }
+}
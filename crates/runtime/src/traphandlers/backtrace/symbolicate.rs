@@ -0,0 +1,184 @@
+//! Resolving a [`Frame`](super::Frame)'s raw `pc` into a human-readable
+//! source location.
+//!
+//! This is a thin layer on top of whatever DWARF/name-section data a
+//! compiled module happens to carry: we ask the embedder (via
+//! [`ModuleLookup`]) which module owns a `pc`, consult that module's
+//! `addr2line` context for a source function/file/line, and otherwise fall
+//! back to the Wasm function index and the name section. Since compiled
+//! function names are frequently a Rust or C++ symbol that was carried
+//! through from a `wasm2c`-style toolchain or from a Wasm module compiled
+//! from a mangled-name-preserving frontend, we run whatever name we do find
+//! through a demangler before handing it back to the caller.
+//!
+//! Because Cranelift inlines callees, a single physical frame's `pc` can
+//! correspond to several source-level functions. When DWARF records
+//! `DW_TAG_inlined_subroutine`s covering that `pc`, we expand the frame into
+//! one [`FrameSymbol`] per nesting level, innermost inlined callee first,
+//! so a printed trace reads the same way a native debugger's would.
+
+use super::Frame;
+
+/// A single logical frame produced by symbolicating a physical
+/// [`Frame`](super::Frame).
+///
+/// Ordinarily there is a one-to-one correspondence between physical frames
+/// and `FrameSymbol`s, but when the physical frame's `pc` falls inside an
+/// inlined callee, symbolication yields one `FrameSymbol` per level of
+/// inlining (see `Backtrace::symbolize`).
+#[derive(Debug, Clone)]
+pub struct FrameSymbol {
+    module: String,
+    func_index: u32,
+    name: Option<String>,
+    file: Option<String>,
+    line: Option<u32>,
+}
+
+impl FrameSymbol {
+    /// The name of the compiled module that this frame belongs to.
+    pub fn module(&self) -> &str {
+        &self.module
+    }
+
+    /// The index, within its module, of the Wasm function this frame is
+    /// executing.
+    pub fn func_index(&self) -> u32 {
+        self.func_index
+    }
+
+    /// The demangled function name for this frame, if one could be
+    /// determined from debug info or the name section.
+    ///
+    /// If demangling fails, this is the raw, possibly-mangled name.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// The source file this frame corresponds to, if debug info is present.
+    pub fn file(&self) -> Option<&str> {
+        self.file.as_deref()
+    }
+
+    /// The source line this frame corresponds to, if debug info is present.
+    pub fn line(&self) -> Option<u32> {
+        self.line
+    }
+}
+
+/// Information about the compiled module that owns a given `pc`, as
+/// resolved by a [`ModuleLookup`] implementation.
+pub struct ModuleInfo<'a> {
+    /// The module's name, used for diagnostics (e.g. `wasmtime::Module::name`).
+    pub name: &'a str,
+    /// The Wasm function index that `pc` falls within.
+    pub func_index: u32,
+    /// The function's name, as recorded in the Wasm name section, if any.
+    pub func_name: Option<&'a str>,
+    /// DWARF debug info for this module, if it was compiled with `-g` (or
+    /// equivalent) and the embedder configured debug info parsing.
+    pub dwarf: Option<&'a addr2line::Context<gimli::EndianSlice<'a, gimli::RunTimeEndian>>>,
+}
+
+/// A source of module information for [`Backtrace::symbolize`](super::Backtrace::symbolize).
+///
+/// Implemented by the embedding layer (e.g. `wasmtime::Store`'s module
+/// registry), which is the only thing that knows which modules are
+/// currently loaded and where their compiled code lives in memory.
+pub trait ModuleLookup {
+    /// Find the module that contains `pc`, if any is currently registered.
+    fn module_for_pc(&self, pc: usize) -> Option<ModuleInfo<'_>>;
+}
+
+pub(super) fn symbolize_frame(lookup: &dyn ModuleLookup, frame: &Frame) -> Vec<FrameSymbol> {
+    let Some(info) = lookup.module_for_pc(frame.pc()) else {
+        return Vec::new();
+    };
+
+    // If we have DWARF info, prefer `find_frames` over a plain
+    // `find_location`: `find_frames` walks `DW_TAG_inlined_subroutine`
+    // ranges covering `pc` and yields one logical `addr2line::Frame` per
+    // nesting level, innermost inlined callee first, which is exactly the
+    // shape debuggers show for inlined call chains. A `pc` with no inlining
+    // at all just yields the single physical frame.
+    if let Some(dwarf) = info.dwarf {
+        if let Ok(mut frames) = dwarf.find_frames(frame.pc() as u64) {
+            let mut symbols = Vec::new();
+            while let Ok(Some(inline_frame)) = frames.next() {
+                // `Function::demangle` already does full Rust/C++-aware
+                // demangling, so only the name-section fallback (which
+                // addr2line never sees) needs to go through our own
+                // `demangle`.
+                let name = match inline_frame.function.as_ref() {
+                    Some(function) => function.demangle().ok().map(|n| n.into_owned()),
+                    None => info.func_name.map(demangle),
+                };
+                let (file, line) = inline_frame
+                    .location
+                    .map(|loc| (loc.file.map(str::to_owned), loc.line))
+                    .unwrap_or((None, None));
+                symbols.push(FrameSymbol {
+                    module: info.name.to_owned(),
+                    func_index: info.func_index,
+                    name,
+                    file,
+                    line,
+                });
+            }
+            if !symbols.is_empty() {
+                return symbols;
+            }
+        }
+    }
+
+    vec![FrameSymbol {
+        module: info.name.to_owned(),
+        func_index: info.func_index,
+        name: info.func_name.map(demangle),
+        file: None,
+        line: None,
+    }]
+}
+
+/// Demangle a raw symbol name, trying Rust's mangling schemes first and
+/// falling back to Itanium C++ demangling, and finally to the raw name if
+/// neither scheme recognizes it.
+fn demangle(name: &str) -> String {
+    if let Ok(demangled) = rustc_demangle::try_demangle(name) {
+        return demangled.to_string();
+    }
+    if let Ok(demangled) = cpp_demangle::Symbol::new(name) {
+        if let Ok(demangled) = demangled.demangle(&cpp_demangle::DemangleOptions::default()) {
+            return demangled;
+        }
+    }
+    name.to_owned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::demangle;
+
+    #[test]
+    fn demangles_rust_v0() {
+        assert_eq!(demangle("_RNvCs4fqI2P2rA04_3foo3bar"), "foo::bar");
+    }
+
+    #[test]
+    fn demangles_rust_legacy() {
+        assert_eq!(
+            demangle("_ZN8mycrate4main17h0123456789abcdefE"),
+            "mycrate::main::h0123456789abcdef"
+        );
+    }
+
+    #[test]
+    fn falls_back_to_itanium_cpp_when_not_rust() {
+        assert_eq!(demangle("_Z4mainv"), "main()");
+    }
+
+    #[test]
+    fn falls_back_to_raw_name_when_unmangled() {
+        assert_eq!(demangle("not_a_mangled_name"), "not_a_mangled_name");
+    }
+}